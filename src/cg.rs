@@ -1,18 +1,320 @@
-use crate::SoftBufferError;
+//! CoreGraphics/CoreAnimation backend for macOS.
+//!
+//! `CGImpl::present_with_damage` and `CGImpl::set_color_space_hint` are implemented here but, in
+//! this tree, `src/lib.rs` (the platform-agnostic `Surface`/`Buffer` API that every other backend
+//! is reached through) doesn't exist, so there's nothing to wire them into yet. A crate user can
+//! only reach them by depending on this module directly rather than through `softbuffer::Surface`.
+//! Once a generic surface module exists, `present_with_damage` belongs behind a
+//! `Surface::present_with_damage(&self, damage: &[Rect])` that forwards to the active backend, and
+//! `set_color_space_hint` behind a similar `Surface::set_color_space_hint`.
+
+use crate::{Rect, SoftBufferError};
 use core_graphics::base::{
     kCGBitmapByteOrder32Little, kCGImageAlphaNoneSkipFirst, kCGRenderingIntentDefault,
 };
 use core_graphics::color_space::CGColorSpace;
 use core_graphics::data_provider::CGDataProvider;
+use core_graphics::geometry::{CGPoint, CGRect, CGSize};
 use core_graphics::image::CGImage;
 use raw_window_handle::AppKitWindowHandle;
 
 use cocoa::appkit::{NSView, NSViewHeightSizable, NSViewWidthSizable, NSWindow};
 use cocoa::base::{id, nil};
+use cocoa::foundation::NSString;
 use cocoa::quartzcore::{transaction, CALayer, ContentsGravity};
 use foreign_types::ForeignType;
+use objc::declare::ClassDecl;
+use objc::runtime::{Class, Object, Sel, BOOL, YES};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Once};
+
+/// Name of the delegate class registered with the Objective-C runtime to answer
+/// `layer:shouldInheritContentsScale:fromWindow:`. Not actually guaranteed unique: if two copies
+/// of this crate end up statically linked into the same process (a diamond dependency), both
+/// will try to register a class under this name, so `layer_delegate_class` has to tolerate the
+/// second registration finding the class already there rather than assuming its own `Once` is
+/// the only thing that could have created it.
+const LAYER_DELEGATE_CLASS_NAME: &str = "SoftBufferCALayerDelegate";
+static REGISTER_LAYER_DELEGATE_CLASS: Once = Once::new();
+
+extern "C" fn layer_should_inherit_contents_scale(
+    _this: &Object,
+    _sel: Sel,
+    _layer: id,
+    _new_scale: f64,
+    _from_window: id,
+) -> BOOL {
+    YES
+}
+
+/// Registers (on first call) and returns the `CALayerDelegate` class that tells CoreAnimation to
+/// automatically propagate a window's backing scale factor onto our layer, so `present` doesn't
+/// have to poll `backingScaleFactor` and call `set_contents_scale` on every frame.
+fn layer_delegate_class() -> &'static Class {
+    REGISTER_LAYER_DELEGATE_CLASS.call_once(|| unsafe {
+        // Someone else (e.g. another statically-linked copy of this crate) may have already
+        // registered this class with the runtime; in that case there's nothing left to do.
+        if Class::get(LAYER_DELEGATE_CLASS_NAME).is_some() {
+            return;
+        }
+
+        // `ClassDecl::new` can still return `None` here if another statically-linked copy of
+        // this crate wins a registration race right after the `Class::get` check above; treat
+        // that the same as having found it already registered, instead of panicking.
+        if let Some(mut decl) = ClassDecl::new(LAYER_DELEGATE_CLASS_NAME, class!(NSObject)) {
+            decl.add_method(
+                sel!(layer:shouldInheritContentsScale:fromWindow:),
+                layer_should_inherit_contents_scale
+                    as extern "C" fn(&Object, Sel, id, f64, id) -> BOOL,
+            );
+            decl.register();
+        }
+    });
+    Class::get(LAYER_DELEGATE_CLASS_NAME).unwrap()
+}
+
+/// Name of the `NSObject` subclass used purely as an `NSNotificationCenter` observer target for
+/// `NSWindowDidChangeScreenNotification`, so `CGImpl` only re-resolves its color space when the
+/// window actually changes screens instead of on every present.
+const DISPLAY_OBSERVER_CLASS_NAME: &str = "SoftBufferDisplayChangeObserver";
+/// Ivar holding the observer's `*const AtomicBool` "the color space needs refreshing" flag,
+/// stashed as a `usize` since `Object::{get,set}_ivar` needs an `Encode` type.
+const DISPLAY_OBSERVER_FLAG_IVAR: &str = "softbufferColorSpaceDirtyFlag";
+static REGISTER_DISPLAY_OBSERVER_CLASS: Once = Once::new();
+
+extern "C" fn display_observer_window_did_change_screen(this: &Object, _sel: Sel, _note: id) {
+    unsafe {
+        let flag_ptr: usize = *this.get_ivar(DISPLAY_OBSERVER_FLAG_IVAR);
+        if flag_ptr != 0 {
+            (*(flag_ptr as *const AtomicBool)).store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+fn display_observer_class() -> &'static Class {
+    REGISTER_DISPLAY_OBSERVER_CLASS.call_once(|| unsafe {
+        // Same cross-linked-copy tolerance as `layer_delegate_class`.
+        if Class::get(DISPLAY_OBSERVER_CLASS_NAME).is_some() {
+            return;
+        }
+
+        if let Some(mut decl) = ClassDecl::new(DISPLAY_OBSERVER_CLASS_NAME, class!(NSObject)) {
+            decl.add_ivar::<usize>(DISPLAY_OBSERVER_FLAG_IVAR);
+            decl.add_method(
+                sel!(windowDidChangeScreen:),
+                display_observer_window_did_change_screen as extern "C" fn(&Object, Sel, id),
+            );
+            decl.register();
+        }
+    });
+    Class::get(DISPLAY_OBSERVER_CLASS_NAME).unwrap()
+}
+
+// Sublayers are tiled on a fixed grid so `present_with_damage` only has to rebuild `CGImage`s
+// for the tiles the damage rects actually touch, instead of re-uploading the whole framebuffer.
+const TILE_SIZE: u32 = 256;
 
-use std::sync::Arc;
+// `kCALayerWidthSizable | kCALayerHeightSizable`: CALayer's legacy autoresizing mask, not
+// exposed by the `cocoa` crate's `CALayer` wrapper, so we set it via `setAutoresizingMask:`
+// directly. Keeps a sublayer's bounds tracking its superlayer's as the window resizes.
+const CA_LAYER_WIDTH_AND_HEIGHT_SIZABLE: u64 = (1 << 1) | (1 << 4);
+
+/// Minimal FFI surface for the bits of `IOSurface.framework` we need to back a [`CALayer`]
+/// with a CPU-writable buffer that CoreAnimation can sample without a copy.
+mod io_surface {
+    use cocoa::base::id;
+    use std::ffi::c_void;
+
+    #[allow(non_camel_case_types)]
+    pub type IOSurfaceRef = *mut c_void;
+
+    #[link(name = "IOSurface", kind = "framework")]
+    extern "C" {
+        pub fn IOSurfaceCreate(properties: id) -> IOSurfaceRef;
+        pub fn IOSurfaceLock(buffer: IOSurfaceRef, options: u32, seed: *mut u32) -> i32;
+        pub fn IOSurfaceUnlock(buffer: IOSurfaceRef, options: u32, seed: *mut u32) -> i32;
+        pub fn IOSurfaceGetBaseAddress(buffer: IOSurfaceRef) -> *mut c_void;
+        pub fn IOSurfaceGetBytesPerRow(buffer: IOSurfaceRef) -> usize;
+        pub fn IOSurfaceIsInUse(buffer: IOSurfaceRef) -> bool;
+        pub fn CFRelease(cf: IOSurfaceRef);
+    }
+}
+
+/// FFI for the handful of `CoreGraphics` display/color-space entry points not wrapped by the
+/// `core-graphics` crate: looking up a display's color space and named color spaces by constant.
+mod cg_color {
+    use std::ffi::c_void;
+
+    #[allow(non_camel_case_types)]
+    pub type CGDirectDisplayID = u32;
+    #[allow(non_camel_case_types)]
+    pub type CGColorSpaceRef = *mut c_void;
+    #[allow(non_camel_case_types)]
+    pub type CFStringRef = *const c_void;
+
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        pub fn CGMainDisplayID() -> CGDirectDisplayID;
+        pub fn CGDisplayCopyColorSpace(display: CGDirectDisplayID) -> CGColorSpaceRef;
+        pub fn CGColorSpaceCreateWithName(name: CFStringRef) -> CGColorSpaceRef;
+
+        pub static kCGColorSpaceSRGB: CFStringRef;
+        pub static kCGColorSpaceDisplayP3: CFStringRef;
+    }
+}
+
+/// Which color space CoreAnimation should treat presented pixels as being encoded in. Getting
+/// this wrong doesn't fail to render, it just renders the wrong colors on wide-gamut displays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpaceHint {
+    /// Track the color space of the display the window currently occupies, re-resolved whenever
+    /// `window` changes screens so dragging it to a different display keeps colors correct. The
+    /// default.
+    MatchDisplay,
+    /// Always interpret pixels as sRGB, regardless of the display.
+    Srgb,
+    /// Always interpret pixels as Display P3, regardless of the display.
+    DisplayP3,
+}
+
+// The keys and 'BGRA' pixel format accepted by `IOSurfaceCreate`'s properties dictionary. Note
+// there's no `IOSurfaceBytesPerRow` here: forcing a tight `width * 4` stride can be rejected by
+// `IOSurfaceCreate` for widths that don't satisfy the format's row alignment, so we let the
+// system choose and read the real stride back afterwards with `IOSurfaceGetBytesPerRow`.
+const K_IO_SURFACE_WIDTH: &str = "IOSurfaceWidth";
+const K_IO_SURFACE_HEIGHT: &str = "IOSurfaceHeight";
+const K_IO_SURFACE_BYTES_PER_ELEMENT: &str = "IOSurfaceBytesPerElement";
+const K_IO_SURFACE_PIXEL_FORMAT: &str = "IOSurfacePixelFormat";
+// FourCC 'BGRA', matching the `kCGBitmapByteOrder32Little | kCGImageAlphaNoneSkipFirst` layout
+// used by the `CGImage` fallback path below.
+const IO_SURFACE_PIXEL_FORMAT_BGRA: u32 = 0x42475241;
+// Don't grow the surface pool past this; if all of these are in use CoreAnimation is holding
+// onto frames for far longer than a sane compositor ever should.
+const MAX_SURFACES: usize = 3;
+
+/// A single `IOSurface` sized to hold one frame's worth of BGRA pixels.
+struct IOSurfaceHandle {
+    surface: io_surface::IOSurfaceRef,
+    width: u32,
+    height: u32,
+    // Real `IOSurfaceGetBytesPerRow`, queried once at creation; may exceed `width * 4` if the
+    // system padded rows for alignment.
+    stride: usize,
+}
+
+impl IOSurfaceHandle {
+    fn new(width: u32, height: u32) -> Option<Self> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        unsafe {
+            let props: id = msg_send![class!(NSMutableDictionary), dictionaryWithCapacity: 4];
+            let set_number = |key: &str, value: i64| {
+                let key = NSString::alloc(nil).init_str(key);
+                let value: id = msg_send![class!(NSNumber), numberWithLongLong: value];
+                let _: () = msg_send![props, setObject: value forKey: key];
+            };
+            set_number(K_IO_SURFACE_WIDTH, width as i64);
+            set_number(K_IO_SURFACE_HEIGHT, height as i64);
+            set_number(K_IO_SURFACE_BYTES_PER_ELEMENT, 4);
+            set_number(
+                K_IO_SURFACE_PIXEL_FORMAT,
+                IO_SURFACE_PIXEL_FORMAT_BGRA as i64,
+            );
+
+            let surface = io_surface::IOSurfaceCreate(props);
+            if surface.is_null() {
+                return None;
+            }
+
+            let stride = io_surface::IOSurfaceGetBytesPerRow(surface);
+            Some(Self {
+                surface,
+                width,
+                height,
+                stride,
+            })
+        }
+    }
+
+    fn matches(&self, width: u32, height: u32) -> bool {
+        self.width == width && self.height == height
+    }
+
+    fn is_in_use(&self) -> bool {
+        unsafe { io_surface::IOSurfaceIsInUse(self.surface) }
+    }
+
+    /// Whether a row of this surface is exactly `width * 4` bytes, i.e. whether it can be
+    /// written to directly as a tightly packed `&mut [u32]` with no stride translation.
+    fn is_contiguous(&self) -> bool {
+        self.stride == self.width as usize * 4
+    }
+
+    /// Locks the surface for CPU access and returns its base address, but only when
+    /// [`is_contiguous`](Self::is_contiguous) — otherwise there's no way to hand back a plain
+    /// `&mut [u32]` without either a copy or leaking the stride into the public API, so the
+    /// caller should fall back to [`write_pixels`](Self::write_pixels) instead. Pair with
+    /// [`unlock`](Self::unlock) once writing is done.
+    fn lock_contiguous(&self) -> Option<*mut u32> {
+        if !self.is_contiguous() {
+            return None;
+        }
+
+        unsafe {
+            io_surface::IOSurfaceLock(self.surface, 0, std::ptr::null_mut());
+            Some(io_surface::IOSurfaceGetBaseAddress(self.surface) as *mut u32)
+        }
+    }
+
+    fn unlock(&self) {
+        unsafe { io_surface::IOSurfaceUnlock(self.surface, 0, std::ptr::null_mut()) };
+    }
+
+    /// Locks the surface and copies `pixels` (tightly packed, `width * height` long) into it,
+    /// respecting `stride`. Used when `!is_contiguous()`, where the surface can't be exposed
+    /// directly as `buffer_mut`'s tightly packed `&mut [u32]`.
+    fn write_pixels(&self, pixels: &[u32]) {
+        unsafe {
+            io_surface::IOSurfaceLock(self.surface, 0, std::ptr::null_mut());
+
+            let base = io_surface::IOSurfaceGetBaseAddress(self.surface) as *mut u8;
+            let row_bytes = self.width as usize * 4;
+            let src: &[u8] = bytemuck::cast_slice(pixels);
+
+            for row in 0..self.height as usize {
+                let src_row = &src[row * row_bytes..(row + 1) * row_bytes];
+                let dst_row =
+                    std::slice::from_raw_parts_mut(base.add(row * self.stride), row_bytes);
+                dst_row.copy_from_slice(src_row);
+            }
+
+            io_surface::IOSurfaceUnlock(self.surface, 0, std::ptr::null_mut());
+        }
+    }
+
+    fn as_contents(&self) -> id {
+        self.surface as id
+    }
+}
+
+impl Drop for IOSurfaceHandle {
+    fn drop(&mut self) {
+        unsafe { io_surface::CFRelease(self.surface) };
+    }
+}
+
+/// What to do with the buffer `buffer_mut` most recently handed out, once `present` is called.
+enum PendingFrame {
+    /// The caller wrote directly into this locked, contiguous-stride surface; just unlock it and
+    /// hand it to the layer.
+    Surface(usize),
+    /// The caller wrote into `CGImpl::scratch`; still needs to reach the layer, either by
+    /// copying into a surface or, failing that, via a fresh `CGImage`.
+    Scratch,
+}
 
 struct Buffer(Vec<u32>);
 
@@ -23,12 +325,40 @@ impl AsRef<[u8]> for Buffer {
 }
 
 pub struct CGImpl {
+    // Attached directly to the view's (AppKit-managed) layer; geometry-flipped so everything
+    // parented under it can use top-left-based coordinates. Transparent and otherwise inert.
+    root_layer: CALayer,
+    // The content layer, parented under `root_layer`. Holds the whole-frame `CGImage`/IOSurface
+    // contents for `present`, and is the parent of the damage tile grid for `present_with_damage`.
     layer: CALayer,
+    layer_delegate: id,
     window: id,
     color_space: CGColorSpace,
-    buffer: Option<Vec<u32>>,
+    color_space_hint: ColorSpaceHint,
+    // `NSNotificationCenter` observer for `NSWindowDidChangeScreenNotification`, telling us when
+    // to bother re-resolving the display color space instead of doing it on every present.
+    display_observer: id,
+    // Set by `display_observer` on the main thread when `window` changes screens; cleared by
+    // `refresh_color_space` once it has re-resolved. Freed (via `Box::from_raw`) in `Drop`.
+    color_space_dirty: *const AtomicBool,
+    // What `present` needs to do with the frame `buffer_mut` most recently handed out, if any.
+    pending: Option<PendingFrame>,
+    // Reused scratch buffer for the non-zero-copy path: either a surface's stride doesn't match
+    // `width * 4`, or no surface could be acquired at all.
+    scratch: Vec<u32>,
     width: u32,
     height: u32,
+    // A small pool of IOSurfaces we flip between so CoreAnimation can sample a frame while we
+    // write the next one. `buffer_mut` locks one directly as the returned `&mut [u32]` when its
+    // stride is contiguous (true zero-copy); otherwise (padded stride, or no surface could be
+    // acquired) `present` copies the scratch buffer into a surface, or builds a `CGImage` when no
+    // surface can be acquired. The result of `IOSurfaceLock` is not checked.
+    surfaces: Vec<IOSurfaceHandle>,
+    // Tile sublayers used by `present_with_damage`, laid out row-major over `tile_cols` columns.
+    // Rebuilt by `resize` whenever the size changes.
+    tiles: Vec<CALayer>,
+    tile_cols: u32,
+    tile_rows: u32,
 }
 
 impl CGImpl {
@@ -37,81 +367,476 @@ impl CGImpl {
         let window: id = msg_send![window, retain];
         let view = handle.ns_view as id;
         let layer = CALayer::new();
+        let root_layer = CALayer::new();
+        let layer_delegate: id;
         unsafe {
-            let subview: id = NSView::alloc(nil).initWithFrame_(NSView::frame(view));
+            let frame = NSView::frame(view);
+            let subview: id = NSView::alloc(nil).initWithFrame_(frame);
+            subview.setAutoresizingMask_(NSViewWidthSizable | NSViewHeightSizable);
+
+            // AppKit's layer coordinate system is bottom-left origin, but the framebuffer's
+            // first pixel is the visual top-left; `root_layer` flips geometry once here so every
+            // content layer underneath it (and their sublayers, like the damage tile grid) can
+            // use top-left-based coordinates directly.
+            root_layer.set_needs_display_on_bounds_change(false);
+            let _: () = msg_send![root_layer.id(), setAnchorPoint: CGPoint::new(0.0, 0.0)];
+            let _: () = msg_send![root_layer.id(), setGeometryFlipped: YES];
+            let _: () =
+                msg_send![root_layer.id(), setAutoresizingMask: CA_LAYER_WIDTH_AND_HEIGHT_SIZABLE];
+            root_layer.set_frame(CGRect::new(
+                &CGPoint::new(0.0, 0.0),
+                &CGSize::new(frame.size.width, frame.size.height),
+            ));
+
             layer.set_contents_gravity(ContentsGravity::TopLeft);
             layer.set_needs_display_on_bounds_change(false);
-            subview.setLayer(layer.id());
-            subview.setAutoresizingMask_(NSViewWidthSizable | NSViewHeightSizable);
+            layer.set_contents_scale(window.backingScaleFactor());
+            // `geometryFlipped` only governs a layer's *own* sublayers' coordinate space, not the
+            // layer itself, so `root_layer` being flipped doesn't help the damage tile grid
+            // parented under `layer` (added in `rebuild_tiles`) — without this, `tile_rect`'s
+            // top-left-based `y = row * TILE_SIZE` would land rows inverted.
+            let _: () = msg_send![layer.id(), setGeometryFlipped: YES];
+            let _: () =
+                msg_send![layer.id(), setAutoresizingMask: CA_LAYER_WIDTH_AND_HEIGHT_SIZABLE];
+            layer.set_frame(CGRect::new(
+                &CGPoint::new(0.0, 0.0),
+                &CGSize::new(frame.size.width, frame.size.height),
+            ));
+            layer_delegate = msg_send![layer_delegate_class(), new];
+            let _: () = msg_send![layer.id(), setDelegate: layer_delegate];
+
+            // Do this in a disabled-actions transaction so attaching the layer tree doesn't
+            // trigger CALayer's default fade-in action.
+            transaction::begin();
+            transaction::set_disable_actions(true);
+            root_layer.add_sublayer(&layer);
+            let _: () = msg_send![subview, setWantsLayer: YES];
+            let view_layer: id = msg_send![subview, layer];
+            let _: () = msg_send![view_layer, addSublayer: root_layer.id()];
+            transaction::commit();
 
             view.addSubview_(subview); // retains subview (+1) = 2
             let _: () = msg_send![subview, release]; // releases subview (-1) = 1
         }
-        let color_space = CGColorSpace::create_device_rgb();
+        let color_space = Self::resolve_display_color_space(window);
+        unsafe {
+            let _: () = msg_send![layer.id(), setColorspace: color_space.as_ptr()];
+        }
+
+        let color_space_dirty = Box::into_raw(Box::new(AtomicBool::new(false)));
+        let display_observer: id;
+        unsafe {
+            display_observer = msg_send![display_observer_class(), new];
+            (*display_observer)
+                .set_ivar::<usize>(DISPLAY_OBSERVER_FLAG_IVAR, color_space_dirty as usize);
+
+            let notification_center: id = msg_send![class!(NSNotificationCenter), defaultCenter];
+            let name = NSString::alloc(nil).init_str("NSWindowDidChangeScreenNotification");
+            let _: () = msg_send![
+                notification_center,
+                addObserver: display_observer
+                selector: sel!(windowDidChangeScreen:)
+                name: name
+                object: window
+            ];
+        }
+
         Ok(Self {
             layer,
+            root_layer,
+            layer_delegate,
             window,
             color_space,
+            color_space_hint: ColorSpaceHint::MatchDisplay,
+            display_observer,
+            color_space_dirty: color_space_dirty as *const AtomicBool,
+            pending: None,
+            scratch: Vec::new(),
             width: 0,
             height: 0,
-            buffer: None,
+            surfaces: Vec::new(),
+            tiles: Vec::new(),
+            tile_cols: 0,
+            tile_rows: 0,
         })
     }
 
     pub fn resize(&mut self, width: u32, height: u32) -> Result<(), SoftBufferError> {
-        self.width = width;
-        self.height = height;
+        if self.width != width || self.height != height {
+            // A pending lock refers to the old framebuffer dimensions; there's nothing
+            // meaningful left to present, so just unlock and discard it.
+            if let Some(PendingFrame::Surface(index)) = self.pending.take() {
+                self.surfaces[index].unlock();
+            }
+            // The pool holds surfaces sized for the old dimensions; drop them and let
+            // `acquire_surface_index` lazily allocate fresh ones for the new size.
+            self.surfaces.clear();
+            self.width = width;
+            self.height = height;
+            self.rebuild_tiles();
+        }
         Ok(())
     }
 
+    /// Tears down the existing tile grid, if any, and lays out fresh tile sublayers covering
+    /// `self.width` x `self.height`. Tiles on the right/bottom edge are clipped to whatever
+    /// remains instead of being padded out to a full `TILE_SIZE` square.
+    fn rebuild_tiles(&mut self) {
+        transaction::begin();
+        transaction::set_disable_actions(true);
+
+        for tile in self.tiles.drain(..) {
+            unsafe { tile.remove_from_superlayer() };
+        }
+
+        if self.width == 0 || self.height == 0 {
+            self.tile_cols = 0;
+            self.tile_rows = 0;
+            transaction::commit();
+            return;
+        }
+
+        self.tile_cols = (self.width + TILE_SIZE - 1) / TILE_SIZE;
+        self.tile_rows = (self.height + TILE_SIZE - 1) / TILE_SIZE;
+
+        // Each tile needs its own contents scale and delegate, same as `self.layer` in `new` —
+        // otherwise tiles render their `CGImage` contents at 1x and look blurry/half-size on a
+        // Retina display.
+        let scale = unsafe { self.window.backingScaleFactor() };
+
+        for row in 0..self.tile_rows {
+            for col in 0..self.tile_cols {
+                let x = col * TILE_SIZE;
+                let y = row * TILE_SIZE;
+                let width = (self.width - x).min(TILE_SIZE);
+                let height = (self.height - y).min(TILE_SIZE);
+
+                let tile = CALayer::new();
+                tile.set_contents_gravity(ContentsGravity::TopLeft);
+                tile.set_needs_display_on_bounds_change(false);
+                tile.set_contents_scale(scale);
+                unsafe {
+                    let _: () = msg_send![tile.id(), setDelegate: self.layer_delegate];
+                }
+                tile.set_frame(CGRect::new(
+                    &CGPoint::new(x as f64, y as f64),
+                    &CGSize::new(width as f64, height as f64),
+                ));
+
+                self.layer.add_sublayer(&tile);
+                self.tiles.push(tile);
+            }
+        }
+
+        transaction::commit();
+    }
+
+    /// Bounds of the tile at `(col, row)` in the grid, clipped to the framebuffer edges.
+    fn tile_rect(&self, col: u32, row: u32) -> (u32, u32, u32, u32) {
+        let x = col * TILE_SIZE;
+        let y = row * TILE_SIZE;
+        let width = (self.width - x).min(TILE_SIZE);
+        let height = (self.height - y).min(TILE_SIZE);
+        (x, y, width, height)
+    }
+
+    /// Overrides how presented pixels are color-managed. Pass [`ColorSpaceHint::MatchDisplay`]
+    /// (the default) to let CoreAnimation use the current display's color space.
+    pub fn set_color_space_hint(&mut self, hint: ColorSpaceHint) {
+        self.color_space_hint = hint;
+        self.color_space = self.resolve_color_space();
+        unsafe { &*self.color_space_dirty }.store(false, Ordering::Relaxed);
+        self.apply_color_space_to_layer();
+    }
+
+    /// Re-resolves `self.color_space` from `self.color_space_hint`, but only when
+    /// `display_observer` has flagged an actual screen change since the last call. Called on
+    /// every present; for [`ColorSpaceHint::MatchDisplay`] this keeps a window dragged to a
+    /// different display picking up that display's color space on the very next frame, without
+    /// re-issuing a `CGDisplayCopyColorSpace` round-trip on every single frame.
+    fn refresh_color_space(&mut self) {
+        if self.color_space_hint != ColorSpaceHint::MatchDisplay {
+            return;
+        }
+        if unsafe { &*self.color_space_dirty }.swap(false, Ordering::Relaxed) {
+            self.color_space = self.resolve_color_space();
+            self.apply_color_space_to_layer();
+        }
+    }
+
+    /// Tags `self.layer` with `self.color_space` via `CALayer.colorspace`. Unlike a `CGImage`
+    /// (which always carries its own embedded color space), an `IOSurface` handed to
+    /// `set_contents` has none, so without this the zero-copy surface path from chunk0-1 would
+    /// silently present surface contents uncolor-managed regardless of `color_space_hint`.
+    fn apply_color_space_to_layer(&self) {
+        unsafe {
+            let _: () = msg_send![self.layer.id(), setColorspace: self.color_space.as_ptr()];
+        }
+    }
+
+    fn resolve_color_space(&self) -> CGColorSpace {
+        match self.color_space_hint {
+            ColorSpaceHint::MatchDisplay => Self::resolve_display_color_space(self.window),
+            ColorSpaceHint::Srgb => unsafe {
+                Self::color_space_from_name(cg_color::kCGColorSpaceSRGB)
+            },
+            ColorSpaceHint::DisplayP3 => unsafe {
+                Self::color_space_from_name(cg_color::kCGColorSpaceDisplayP3)
+            },
+        }
+    }
+
+    unsafe fn color_space_from_name(name: cg_color::CFStringRef) -> CGColorSpace {
+        let space_ref = cg_color::CGColorSpaceCreateWithName(name);
+        if space_ref.is_null() {
+            CGColorSpace::create_device_rgb()
+        } else {
+            CGColorSpace::from_ptr(space_ref as *mut _)
+        }
+    }
+
+    /// The color space of the display `window` currently occupies, falling back to the main
+    /// display and then device RGB if that can't be determined.
+    fn resolve_display_color_space(window: id) -> CGColorSpace {
+        unsafe {
+            let display_id =
+                Self::display_id_for_window(window).unwrap_or_else(cg_color::CGMainDisplayID);
+            let space_ref = cg_color::CGDisplayCopyColorSpace(display_id);
+            if space_ref.is_null() {
+                CGColorSpace::create_device_rgb()
+            } else {
+                CGColorSpace::from_ptr(space_ref as *mut _)
+            }
+        }
+    }
+
+    /// The `CGDirectDisplayID` of the `NSScreen` currently showing `window`, or `None` if the
+    /// window isn't on any screen (e.g. it's currently hidden/miniaturized).
+    unsafe fn display_id_for_window(window: id) -> Option<cg_color::CGDirectDisplayID> {
+        let screen: id = msg_send![window, screen];
+        if screen == nil {
+            return None;
+        }
+
+        let device_description: id = msg_send![screen, deviceDescription];
+        let key = NSString::alloc(nil).init_str("NSScreenNumber");
+        let number: id = msg_send![device_description, objectForKey: key];
+        if number == nil {
+            return None;
+        }
+
+        Some(msg_send![number, unsignedIntValue])
+    }
+
+    /// Returns a tightly packed `width * height` buffer to draw into. When the acquired
+    /// `IOSurface`'s stride happens to be `width * 4`, this is the surface itself, locked for
+    /// direct CPU access — true zero-copy, since `present` then only has to unlock it and hand
+    /// it to the layer. Otherwise (padded stride, or no surface available) it's a reused scratch
+    /// buffer that `present` copies from.
     pub fn buffer_mut(&mut self) -> Result<&mut [u32], SoftBufferError> {
-        if self.buffer.is_none() {
-            self.buffer = Some(Vec::new());
+        let len = self.width as usize * self.height as usize;
+
+        // A previous `buffer_mut` call without an intervening `present` left a surface locked
+        // for CPU access; `acquire_surface_index` only checks `IOSurfaceIsInUse` (compositor
+        // sampling), not our own CPU lock, so it could otherwise hand the same surface back and
+        // lock it a second time. Unlock it before picking the next surface.
+        if let Some(PendingFrame::Surface(index)) = self.pending.take() {
+            self.surfaces[index].unlock();
+        }
+
+        if let Some(index) = self.acquire_surface_index() {
+            if let Some(ptr) = self.surfaces[index].lock_contiguous() {
+                self.pending = Some(PendingFrame::Surface(index));
+                return Ok(unsafe { std::slice::from_raw_parts_mut(ptr, len) });
+            }
         }
-        let buffer = self.buffer.as_mut().unwrap();
-        buffer.resize(self.width as usize * self.height as usize, 0);
-        Ok(buffer.as_mut())
+
+        self.pending = Some(PendingFrame::Scratch);
+        self.scratch.resize(len, 0);
+        Ok(self.scratch.as_mut_slice())
+    }
+
+    /// Picks a surface that isn't currently being sampled by the compositor, allocating one (or,
+    /// if both existing surfaces are busy, a third) if needed. Returns `None` if allocation
+    /// fails, in which case the caller should fall back to the `CGImage` path.
+    fn acquire_surface_index(&mut self) -> Option<usize> {
+        self.surfaces
+            .retain(|surface| surface.matches(self.width, self.height));
+
+        if let Some(index) = self.surfaces.iter().position(|s| !s.is_in_use()) {
+            return Some(index);
+        }
+
+        if self.surfaces.len() < MAX_SURFACES {
+            let surface = IOSurfaceHandle::new(self.width, self.height)?;
+            self.surfaces.push(surface);
+            return Some(self.surfaces.len() - 1);
+        }
+
+        None
     }
 
     pub fn present(&mut self) -> Result<(), SoftBufferError> {
-        if let Some(buffer) = self.buffer.take() {
-            let data_provider = CGDataProvider::from_buffer(Arc::new(Buffer(buffer)));
-            let image = CGImage::new(
-                self.width as usize,
-                self.height as usize,
-                8,
-                32,
-                (self.width * 4) as usize,
-                &self.color_space,
-                kCGBitmapByteOrder32Little | kCGImageAlphaNoneSkipFirst,
-                &data_provider,
-                false,
-                kCGRenderingIntentDefault,
-            );
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
 
-            // The CALayer has a default action associated with a change in the layer contents, causing
-            // a quarter second fade transition to happen every time a new buffer is applied. This can
-            // be mitigated by wrapping the operation in a transaction and disabling all actions.
-            transaction::begin();
-            transaction::set_disable_actions(true);
+        self.refresh_color_space();
 
-            unsafe {
-                self.layer
-                    .set_contents_scale(self.window.backingScaleFactor());
-                self.layer.set_contents(image.as_ptr() as id);
-            };
+        // The CALayer has a default action associated with a change in the layer contents, causing
+        // a quarter second fade transition to happen every time a new buffer is applied. This can
+        // be mitigated by wrapping the operation in a transaction and disabling all actions.
+        transaction::begin();
+        transaction::set_disable_actions(true);
 
-            transaction::commit();
+        // Each arm resolves the id to hand to the layer into a local, non-`self`-borrowing
+        // `contents: id` and calls `set_contents` while the backing object (surface or
+        // `CGImage`) is still in scope, so we never hold a borrow derived from `&mut self`
+        // (e.g. from `acquire_surface_index`) at the same time as the `set_contents` call.
+        match pending {
+            PendingFrame::Surface(index) => {
+                let surface = &self.surfaces[index];
+                surface.unlock();
+                let contents = surface.as_contents();
+                unsafe { self.layer.set_contents(contents) };
+            }
+            PendingFrame::Scratch => {
+                if let Some(index) = self.acquire_surface_index() {
+                    let surface = &self.surfaces[index];
+                    surface.write_pixels(&self.scratch);
+                    let contents = surface.as_contents();
+                    unsafe { self.layer.set_contents(contents) };
+                } else {
+                    let data_provider =
+                        CGDataProvider::from_buffer(Arc::new(Buffer(self.scratch.clone())));
+                    let image = CGImage::new(
+                        self.width as usize,
+                        self.height as usize,
+                        8,
+                        32,
+                        (self.width * 4) as usize,
+                        &self.color_space,
+                        kCGBitmapByteOrder32Little | kCGImageAlphaNoneSkipFirst,
+                        &data_provider,
+                        false,
+                        kCGRenderingIntentDefault,
+                    );
+                    unsafe { self.layer.set_contents(image.as_ptr() as id) };
+                }
+            }
         }
 
+        transaction::commit();
+
         Ok(())
     }
+
+    /// Like [`present`](Self::present), but only rebuilds the tiles that intersect `damage`,
+    /// leaving every other tile's `CGImage` contents untouched. Falls back to rebuilding
+    /// nothing if the buffer hasn't changed since the last present.
+    pub fn present_with_damage(&mut self, damage: &[Rect]) -> Result<(), SoftBufferError> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+
+        self.refresh_color_space();
+
+        if self.tiles.is_empty() {
+            self.rebuild_tiles();
+        }
+
+        transaction::begin();
+        transaction::set_disable_actions(true);
+
+        // A view of the full frame's pixels, regardless of whether `buffer_mut` handed back a
+        // locked surface or the scratch buffer.
+        let len = self.width as usize * self.height as usize;
+        let pixels: &[u32] = match pending {
+            PendingFrame::Surface(index) => unsafe {
+                let base = io_surface::IOSurfaceGetBaseAddress(self.surfaces[index].surface);
+                std::slice::from_raw_parts(base as *const u32, len)
+            },
+            PendingFrame::Scratch => &self.scratch,
+        };
+
+        for row in 0..self.tile_rows {
+            for col in 0..self.tile_cols {
+                let (tile_x, tile_y, tile_w, tile_h) = self.tile_rect(col, row);
+                if !damage
+                    .iter()
+                    .any(|rect| rect_intersects_tile(rect, tile_x, tile_y, tile_w, tile_h))
+                {
+                    continue;
+                }
+
+                let mut tile_pixels = vec![0u32; (tile_w * tile_h) as usize];
+                for line in 0..tile_h {
+                    let src_start = ((tile_y + line) * self.width + tile_x) as usize;
+                    let src_row = &pixels[src_start..src_start + tile_w as usize];
+                    let dst_start = (line * tile_w) as usize;
+                    tile_pixels[dst_start..dst_start + tile_w as usize].copy_from_slice(src_row);
+                }
+
+                let data_provider = CGDataProvider::from_buffer(Arc::new(Buffer(tile_pixels)));
+                let image = CGImage::new(
+                    tile_w as usize,
+                    tile_h as usize,
+                    8,
+                    32,
+                    (tile_w * 4) as usize,
+                    &self.color_space,
+                    kCGBitmapByteOrder32Little | kCGImageAlphaNoneSkipFirst,
+                    &data_provider,
+                    false,
+                    kCGRenderingIntentDefault,
+                );
+
+                let tile = &self.tiles[(row * self.tile_cols + col) as usize];
+                unsafe { tile.set_contents(image.as_ptr() as id) };
+            }
+        }
+
+        if let PendingFrame::Surface(index) = pending {
+            self.surfaces[index].unlock();
+        }
+
+        transaction::commit();
+
+        Ok(())
+    }
+}
+
+/// Whether `rect` overlaps the tile occupying `[tile_x, tile_x + tile_w) x [tile_y, tile_y + tile_h)`.
+fn rect_intersects_tile(rect: &Rect, tile_x: u32, tile_y: u32, tile_w: u32, tile_h: u32) -> bool {
+    let rect_x1 = rect.x + rect.width.get();
+    let rect_y1 = rect.y + rect.height.get();
+    let tile_x1 = tile_x + tile_w;
+    let tile_y1 = tile_y + tile_h;
+
+    rect.x < tile_x1 && rect_x1 > tile_x && rect.y < tile_y1 && rect_y1 > tile_y
 }
 
 impl Drop for CGImpl {
     fn drop(&mut self) {
+        if let Some(PendingFrame::Surface(index)) = self.pending.take() {
+            self.surfaces[index].unlock();
+        }
+
         unsafe {
+            // `CALayer.delegate` is a weak reference, so we own the only strong reference to
+            // `layer_delegate` and must release it ourselves.
+            let _: () = msg_send![self.layer.id(), setDelegate: nil];
+            let _: () = msg_send![self.layer_delegate, release];
+
+            let notification_center: id = msg_send![class!(NSNotificationCenter), defaultCenter];
+            let _: () = msg_send![notification_center, removeObserver: self.display_observer];
+            let _: () = msg_send![self.display_observer, release];
+
             let _: () = msg_send![self.window, release];
         }
+
+        // Reclaim and drop the flag `display_observer` was storing a raw pointer to; nothing can
+        // still be using it once the observer above is unregistered and released.
+        drop(unsafe { Box::from_raw(self.color_space_dirty as *mut AtomicBool) });
     }
 }